@@ -1,23 +1,608 @@
+use std::error::Error;
+use std::fmt;
+
 use common::Rectangle;
 use feat::FeatureMap;
 use math;
 
+/// Error returned by `SurfMlpFeatureMap::compute_slice` when `input` is
+/// too small for the given dimensions.
+#[derive(Debug)]
+pub enum FeatureError {
+    BufferTooSmall { expected: usize, actual: usize },
+}
+
+impl fmt::Display for FeatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FeatureError::BufferTooSmall { expected, actual } => write!(
+                f,
+                "input buffer too small: expected at least {} bytes, got {}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl Error for FeatureError {}
+
+/// Returns an error if `actual` is too few bytes to hold a `width x
+/// height` frame.
+fn check_buffer_len(actual: usize, width: u32, height: u32) -> Result<(), FeatureError> {
+    let expected = (width as usize) * (height as usize);
+    if actual < expected {
+        return Err(FeatureError::BufferTooSmall { expected, actual });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod buffer_len_tests {
+    use super::{check_buffer_len, FeatureError};
+
+    #[test]
+    fn rejects_buffer_shorter_than_frame() {
+        match check_buffer_len(11, 4, 3) {
+            Err(FeatureError::BufferTooSmall { expected: 12, actual: 11 }) => {}
+            other => panic!("expected BufferTooSmall{{expected: 12, actual: 11}}, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accepts_buffer_at_least_as_large_as_frame() {
+        assert!(check_buffer_len(12, 4, 3).is_ok());
+        assert!(check_buffer_len(13, 4, 3).is_ok());
+    }
+}
+
+/// Precision used to accumulate the L2-normalized SURF descriptors handed
+/// to the downstream MLP classifier. Defaults to `f32`; build with the
+/// `f64` cargo feature to switch the whole pipeline to `f64` accumulation
+/// instead, trading memory and speed for a little extra precision on
+/// borderline detection scores.
+#[cfg(not(feature = "f64"))]
+pub type Float = f32;
+#[cfg(feature = "f64")]
+pub type Float = f64;
+
+/// Elementwise `dest[i] += src[i]`. SIMD-accelerated under the `simd`
+/// cargo feature (SSE2/AVX2 on x86, NEON on aarch64), picked at runtime;
+/// always falls back to a plain scalar loop. Used by `integral`'s
+/// vertical/horizontal prefix sums, the hottest loops in a frame's
+/// feature-map computation.
+fn vector_add_assign(dest: &mut [i32], src: &[i32]) {
+    debug_assert_eq!(dest.len(), src.len());
+
+    #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { simd_x86::add_assign_avx2(dest, src) };
+        }
+        if is_x86_feature_detected!("sse2") {
+            return unsafe { simd_x86::add_assign_sse2(dest, src) };
+        }
+    }
+    #[cfg(all(feature = "simd", target_arch = "aarch64"))]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return unsafe { simd_aarch64::add_assign_neon(dest, src) };
+        }
+    }
+
+    for (d, &s) in dest.iter_mut().zip(src.iter()) {
+        *d += s;
+    }
+}
+
+/// Elementwise `dest[i] = a[i] - b[i]`. Same dispatch as
+/// `vector_add_assign`; used by `compute_grad_x`/`compute_grad_y`.
+fn vector_sub_into(dest: &mut [i32], a: &[i32], b: &[i32]) {
+    debug_assert_eq!(dest.len(), a.len());
+    debug_assert_eq!(dest.len(), b.len());
+
+    #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { simd_x86::sub_into_avx2(dest, a, b) };
+        }
+        if is_x86_feature_detected!("sse2") {
+            return unsafe { simd_x86::sub_into_sse2(dest, a, b) };
+        }
+    }
+    #[cfg(all(feature = "simd", target_arch = "aarch64"))]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return unsafe { simd_aarch64::sub_into_neon(dest, a, b) };
+        }
+    }
+
+    for ((d, &x), &y) in dest.iter_mut().zip(a.iter()).zip(b.iter()) {
+        *d = x - y;
+    }
+}
+
+/// Elementwise `dest[i] = src[i].abs()`. Same dispatch as
+/// `vector_add_assign`; used by `compute_integral_images` to build the
+/// absolute-gradient channels.
+fn vector_abs_into(dest: &mut [i32], src: &[i32]) {
+    debug_assert_eq!(dest.len(), src.len());
+
+    #[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { simd_x86::abs_into_avx2(dest, src) };
+        }
+        if is_x86_feature_detected!("sse2") {
+            return unsafe { simd_x86::abs_into_sse2(dest, src) };
+        }
+    }
+    #[cfg(all(feature = "simd", target_arch = "aarch64"))]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return unsafe { simd_aarch64::abs_into_neon(dest, src) };
+        }
+    }
+
+    for (d, &s) in dest.iter_mut().zip(src.iter()) {
+        *d = s.abs();
+    }
+}
+
+#[cfg(test)]
+mod vector_ops_tests {
+    use super::{vector_abs_into, vector_add_assign, vector_sub_into};
+
+    // Covers lengths on both sides of the SIMD kernels' widest chunk (8,
+    // for AVX2) so the scalar remainder tail is exercised along with the
+    // vectorized part, whether or not the `simd` feature is enabled.
+    const LENGTHS: [usize; 5] = [0, 1, 7, 8, 13];
+
+    fn sample(len: usize, offset: i32) -> Vec<i32> {
+        (0..len as i32).map(|i| i * 3 - offset).collect()
+    }
+
+    #[test]
+    fn add_assign_matches_scalar_reference() {
+        for &len in LENGTHS.iter() {
+            let a = sample(len, 5);
+            let b = sample(len, 11);
+
+            let mut got = a.clone();
+            vector_add_assign(&mut got, &b);
+
+            let want: Vec<i32> = a.iter().zip(b.iter()).map(|(&x, &y)| x + y).collect();
+            assert_eq!(got, want, "len={}", len);
+        }
+    }
+
+    #[test]
+    fn sub_into_matches_scalar_reference() {
+        for &len in LENGTHS.iter() {
+            let a = sample(len, 5);
+            let b = sample(len, 11);
+
+            let mut got = vec![0; len];
+            vector_sub_into(&mut got, &a, &b);
+
+            let want: Vec<i32> = a.iter().zip(b.iter()).map(|(&x, &y)| x - y).collect();
+            assert_eq!(got, want, "len={}", len);
+        }
+    }
+
+    #[test]
+    fn abs_into_matches_scalar_reference() {
+        for &len in LENGTHS.iter() {
+            let a = sample(len, 5);
+
+            let mut got = vec![0; len];
+            vector_abs_into(&mut got, &a);
+
+            let want: Vec<i32> = a.iter().map(|&x| x.abs()).collect();
+            assert_eq!(got, want, "len={}", len);
+        }
+    }
+}
+
+#[cfg(all(feature = "simd", any(target_arch = "x86", target_arch = "x86_64")))]
+mod simd_x86 {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn add_assign_avx2(dest: &mut [i32], src: &[i32]) {
+        let mut d_chunks = dest.chunks_exact_mut(8);
+        let mut s_chunks = src.chunks_exact(8);
+        for (d, s) in (&mut d_chunks).zip(&mut s_chunks) {
+            let a = _mm256_loadu_si256(d.as_ptr() as *const __m256i);
+            let b = _mm256_loadu_si256(s.as_ptr() as *const __m256i);
+            _mm256_storeu_si256(d.as_mut_ptr() as *mut __m256i, _mm256_add_epi32(a, b));
+        }
+        for (d, &s) in d_chunks.into_remainder().iter_mut().zip(s_chunks.remainder().iter()) {
+            *d += s;
+        }
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn add_assign_sse2(dest: &mut [i32], src: &[i32]) {
+        let mut d_chunks = dest.chunks_exact_mut(4);
+        let mut s_chunks = src.chunks_exact(4);
+        for (d, s) in (&mut d_chunks).zip(&mut s_chunks) {
+            let a = _mm_loadu_si128(d.as_ptr() as *const __m128i);
+            let b = _mm_loadu_si128(s.as_ptr() as *const __m128i);
+            _mm_storeu_si128(d.as_mut_ptr() as *mut __m128i, _mm_add_epi32(a, b));
+        }
+        for (d, &s) in d_chunks.into_remainder().iter_mut().zip(s_chunks.remainder().iter()) {
+            *d += s;
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn sub_into_avx2(dest: &mut [i32], a: &[i32], b: &[i32]) {
+        let mut d_chunks = dest.chunks_exact_mut(8);
+        let mut a_chunks = a.chunks_exact(8);
+        let mut b_chunks = b.chunks_exact(8);
+        for ((d, x), y) in (&mut d_chunks).zip(&mut a_chunks).zip(&mut b_chunks) {
+            let va = _mm256_loadu_si256(x.as_ptr() as *const __m256i);
+            let vb = _mm256_loadu_si256(y.as_ptr() as *const __m256i);
+            _mm256_storeu_si256(d.as_mut_ptr() as *mut __m256i, _mm256_sub_epi32(va, vb));
+        }
+        for ((d, &x), &y) in d_chunks.into_remainder().iter_mut().zip(a_chunks.remainder().iter()).zip(b_chunks.remainder().iter()) {
+            *d = x - y;
+        }
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn sub_into_sse2(dest: &mut [i32], a: &[i32], b: &[i32]) {
+        let mut d_chunks = dest.chunks_exact_mut(4);
+        let mut a_chunks = a.chunks_exact(4);
+        let mut b_chunks = b.chunks_exact(4);
+        for ((d, x), y) in (&mut d_chunks).zip(&mut a_chunks).zip(&mut b_chunks) {
+            let va = _mm_loadu_si128(x.as_ptr() as *const __m128i);
+            let vb = _mm_loadu_si128(y.as_ptr() as *const __m128i);
+            _mm_storeu_si128(d.as_mut_ptr() as *mut __m128i, _mm_sub_epi32(va, vb));
+        }
+        for ((d, &x), &y) in d_chunks.into_remainder().iter_mut().zip(a_chunks.remainder().iter()).zip(b_chunks.remainder().iter()) {
+            *d = x - y;
+        }
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn abs_into_avx2(dest: &mut [i32], src: &[i32]) {
+        let mut d_chunks = dest.chunks_exact_mut(8);
+        let mut s_chunks = src.chunks_exact(8);
+        for (d, s) in (&mut d_chunks).zip(&mut s_chunks) {
+            let v = _mm256_loadu_si256(s.as_ptr() as *const __m256i);
+            _mm256_storeu_si256(d.as_mut_ptr() as *mut __m256i, _mm256_abs_epi32(v));
+        }
+        for (d, &s) in d_chunks.into_remainder().iter_mut().zip(s_chunks.remainder().iter()) {
+            *d = s.abs();
+        }
+    }
+
+    // `_mm_abs_epi32` needs SSSE3, so the SSE2 tier uses the classic
+    // sign-mask trick instead: `(v ^ (v >> 31)) - (v >> 31)`.
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn abs_into_sse2(dest: &mut [i32], src: &[i32]) {
+        let mut d_chunks = dest.chunks_exact_mut(4);
+        let mut s_chunks = src.chunks_exact(4);
+        for (d, s) in (&mut d_chunks).zip(&mut s_chunks) {
+            let v = _mm_loadu_si128(s.as_ptr() as *const __m128i);
+            let sign_mask = _mm_srai_epi32(v, 31);
+            let abs = _mm_sub_epi32(_mm_xor_si128(v, sign_mask), sign_mask);
+            _mm_storeu_si128(d.as_mut_ptr() as *mut __m128i, abs);
+        }
+        for (d, &s) in d_chunks.into_remainder().iter_mut().zip(s_chunks.remainder().iter()) {
+            *d = s.abs();
+        }
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "aarch64"))]
+mod simd_aarch64 {
+    use std::arch::aarch64::*;
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn add_assign_neon(dest: &mut [i32], src: &[i32]) {
+        let mut d_chunks = dest.chunks_exact_mut(4);
+        let mut s_chunks = src.chunks_exact(4);
+        for (d, s) in (&mut d_chunks).zip(&mut s_chunks) {
+            let a = vld1q_s32(d.as_ptr());
+            let b = vld1q_s32(s.as_ptr());
+            vst1q_s32(d.as_mut_ptr(), vaddq_s32(a, b));
+        }
+        for (d, &s) in d_chunks.into_remainder().iter_mut().zip(s_chunks.remainder().iter()) {
+            *d += s;
+        }
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn sub_into_neon(dest: &mut [i32], a: &[i32], b: &[i32]) {
+        let mut d_chunks = dest.chunks_exact_mut(4);
+        let mut a_chunks = a.chunks_exact(4);
+        let mut b_chunks = b.chunks_exact(4);
+        for ((d, x), y) in (&mut d_chunks).zip(&mut a_chunks).zip(&mut b_chunks) {
+            let va = vld1q_s32(x.as_ptr());
+            let vb = vld1q_s32(y.as_ptr());
+            vst1q_s32(d.as_mut_ptr(), vsubq_s32(va, vb));
+        }
+        for ((d, &x), &y) in d_chunks.into_remainder().iter_mut().zip(a_chunks.remainder().iter()).zip(b_chunks.remainder().iter()) {
+            *d = x - y;
+        }
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn abs_into_neon(dest: &mut [i32], src: &[i32]) {
+        let mut d_chunks = dest.chunks_exact_mut(4);
+        let mut s_chunks = src.chunks_exact(4);
+        for (d, s) in (&mut d_chunks).zip(&mut s_chunks) {
+            let v = vld1q_s32(s.as_ptr());
+            vst1q_s32(d.as_mut_ptr(), vabsq_s32(v));
+        }
+        for (d, &s) in d_chunks.into_remainder().iter_mut().zip(s_chunks.remainder().iter()) {
+            *d = s.abs();
+        }
+    }
+}
+
 pub struct SurfMlpFeatureMap {
     roi: Option<Rectangle>,
+    window_origin: (u32, u32),
     width: u32,
     height: u32,
     length: usize,
-    buf_valid_reset: bool,
     feature_pool: FeaturePool,
     feature_vectors: Vec<Vec<i32>>,
-    feature_vectors_normalized: Vec<Vec<f32>>,
+    feature_vectors_normalized: Vec<Vec<Float>>,
     feature_valid_indicators: Vec<bool>,
     grad_x: Vec<i32>,
     grad_y: Vec<i32>,
-    int_img: Vec<i32>,
+    int_img: Channels,
     img_buf: Vec<i32>,
 }
 
+/// Owns the interleaved `num_channel`-wide integral-image buffer used for
+/// the SURF gradient statistics. Channels stay interleaved per pixel for
+/// cache locality. `resize`, `row_mut` and `fill_channel` keep their
+/// offset arithmetic inside this type, checked in debug builds;
+/// `mask_integral_channel` and `integral` still compute their own offsets
+/// into `data` directly.
+struct Channels {
+    width: usize,
+    height: usize,
+    num_channel: usize,
+    data: Vec<i32>,
+}
+
+impl Channels {
+    fn new(num_channel: usize) -> Self {
+        Channels { width: 0, height: 0, num_channel, data: vec![] }
+    }
+
+    fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.data.resize(width * height * self.num_channel, 0);
+    }
+
+    fn row_mut(&mut self, r: usize) -> &mut [i32] {
+        debug_assert!(r < self.height);
+        let width = self.width * self.num_channel;
+        let start = r * width;
+        &mut self.data[start..start + width]
+    }
+
+    /// Writes `src[i]` into channels `ch` and `ch + 2` of pixel `i`, for
+    /// every pixel covered by `src` (`src.len()` must equal `width *
+    /// height`). This is how the gradient and absolute-gradient planes
+    /// get duplicated into the 8-channel integral image ahead of the
+    /// vertical/horizontal prefix sums.
+    fn fill_channel(&mut self, src: &[i32], ch: usize) {
+        debug_assert_eq!(src.len(), self.width * self.height);
+        for (dest, &s) in self.data.chunks_exact_mut(self.num_channel).zip(src.iter()) {
+            dest[ch] = s;
+            dest[ch + 2] = s;
+        }
+    }
+}
+
+/// Pixel layout of a buffer passed to `SurfMlpFeatureMap::compute_with_format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    Gray8,
+    Rgb8,
+    Bgr8,
+    Rgba8,
+    Bgra8,
+}
+
+impl PixelFormat {
+    fn channels(self) -> usize {
+        match self {
+            PixelFormat::Gray8 => 1,
+            PixelFormat::Rgb8 | PixelFormat::Bgr8 => 3,
+            PixelFormat::Rgba8 | PixelFormat::Bgra8 => 4,
+        }
+    }
+
+    /// Offset of the blue channel within a pixel; `None` for `Gray8`,
+    /// which needs no conversion. Green always sits at offset 1 and red
+    /// at `2 - blue_index`, so callers can derive all three channel
+    /// offsets from this one value regardless of RGB/BGR ordering.
+    fn blue_index(self) -> Option<usize> {
+        match self {
+            PixelFormat::Gray8 => None,
+            PixelFormat::Rgb8 | PixelFormat::Rgba8 => Some(2),
+            PixelFormat::Bgr8 | PixelFormat::Bgra8 => Some(0),
+        }
+    }
+}
+
+/// Fixed-point RGB-to-luma conversion, the same weights OpenCV's
+/// `cvtColor` uses for `BGR2GRAY`/`RGB2GRAY`.
+fn rgb_to_luma(r: u32, g: u32, b: u32) -> u8 {
+    ((77 * r + 150 * g + 29 * b) >> 8) as u8
+}
+
+#[cfg(test)]
+mod rgb_to_luma_tests {
+    use super::rgb_to_luma;
+
+    #[test]
+    fn converts_known_rgb_triples() {
+        assert_eq!(rgb_to_luma(0, 0, 0), 0);
+        assert_eq!(rgb_to_luma(255, 255, 255), 255);
+        assert_eq!(rgb_to_luma(255, 0, 0), 76);
+        assert_eq!(rgb_to_luma(0, 255, 0), 149);
+        assert_eq!(rgb_to_luma(0, 0, 255), 28);
+    }
+}
+
+#[cfg(test)]
+mod compute_with_format_tests {
+    use super::{rgb_to_luma, Channels, FeaturePool, PixelFormat, SurfMlpFeatureMap};
+    use feat::FeatureMap;
+
+    // See `integral_image_tests::test_feature_map`: bypasses
+    // `SurfMlpFeatureMap::new`, which hangs on the pre-existing zero
+    // step sizes in `FeaturePool::create`.
+    fn empty_feature_map() -> SurfMlpFeatureMap {
+        SurfMlpFeatureMap {
+            roi: None,
+            window_origin: (0, 0),
+            width: 0,
+            height: 0,
+            length: 0,
+            feature_pool: FeaturePool::new(),
+            feature_vectors: vec![],
+            feature_vectors_normalized: vec![],
+            feature_valid_indicators: vec![],
+            grad_x: vec![],
+            grad_y: vec![],
+            int_img: Channels::new(FeaturePool::K_NUM_INT_CHANNEL as usize),
+            img_buf: vec![],
+        }
+    }
+
+    #[test]
+    fn rgb8_matches_compute_on_the_manually_converted_luma() {
+        let pixels = [
+            (10, 200, 50), (250, 5, 80), (0, 0, 0), (255, 255, 255),
+            (60, 60, 200), (180, 90, 10), (33, 180, 33), (90, 40, 220),
+            (255, 0, 0), (0, 255, 0), (0, 0, 255), (128, 64, 32),
+        ];
+        let rgb: Vec<u8> = pixels.iter().flat_map(|&(r, g, b)| vec![r, g, b]).collect();
+        let luma: Vec<u8> = pixels.iter().map(|&(r, g, b)| rgb_to_luma(r as u32, g as u32, b as u32)).collect();
+
+        let mut via_format = empty_feature_map();
+        via_format.compute_with_format(rgb.as_ptr(), 4, 3, PixelFormat::Rgb8);
+
+        let mut via_luma = empty_feature_map();
+        via_luma.compute(luma.as_ptr(), 4, 3);
+
+        assert_eq!(via_format.grad_x, via_luma.grad_x);
+        assert_eq!(via_format.grad_y, via_luma.grad_y);
+    }
+
+    #[test]
+    fn bgr8_reads_red_and_blue_from_swapped_offsets() {
+        // Every pixel has a distinct red and blue byte, so mixing up
+        // `blue_idx`/`red_idx` for this format would change the luma.
+        let pixels = [
+            (10, 200, 50), (250, 5, 80), (1, 1, 250), (255, 255, 1),
+            (60, 60, 200), (180, 90, 10), (33, 180, 33), (90, 40, 220),
+            (255, 1, 1), (1, 255, 1), (1, 1, 255), (128, 64, 32),
+        ];
+        let bgr: Vec<u8> = pixels.iter().flat_map(|&(r, g, b)| vec![b, g, r]).collect();
+        let luma: Vec<u8> = pixels.iter().map(|&(r, g, b)| rgb_to_luma(r as u32, g as u32, b as u32)).collect();
+
+        let mut via_format = empty_feature_map();
+        via_format.compute_with_format(bgr.as_ptr(), 4, 3, PixelFormat::Bgr8);
+
+        let mut via_luma = empty_feature_map();
+        via_luma.compute(luma.as_ptr(), 4, 3);
+
+        assert_eq!(via_format.grad_x, via_luma.grad_x);
+        assert_eq!(via_format.grad_y, via_luma.grad_y);
+    }
+
+    #[test]
+    fn rgba8_skips_the_alpha_byte() {
+        let pixels = [
+            (10, 200, 50, 128), (250, 5, 80, 10), (0, 0, 0, 255), (255, 255, 255, 0),
+            (60, 60, 200, 64), (180, 90, 10, 200), (33, 180, 33, 5), (90, 40, 220, 99),
+            (255, 0, 0, 0), (0, 255, 0, 255), (0, 0, 255, 128), (128, 64, 32, 1),
+        ];
+        let rgba: Vec<u8> = pixels.iter().flat_map(|&(r, g, b, a)| vec![r, g, b, a]).collect();
+        let luma: Vec<u8> = pixels.iter().map(|&(r, g, b, _)| rgb_to_luma(r as u32, g as u32, b as u32)).collect();
+
+        let mut via_format = empty_feature_map();
+        via_format.compute_with_format(rgba.as_ptr(), 4, 3, PixelFormat::Rgba8);
+
+        let mut via_luma = empty_feature_map();
+        via_luma.compute(luma.as_ptr(), 4, 3);
+
+        assert_eq!(via_format.grad_x, via_luma.grad_x);
+        assert_eq!(via_format.grad_y, via_luma.grad_y);
+    }
+}
+
+#[cfg(test)]
+mod compute_slice_tests {
+    use super::{Channels, FeatureError, FeaturePool, SurfMlpFeatureMap};
+    use feat::FeatureMap;
+
+    // See `integral_image_tests::test_feature_map`: bypasses
+    // `SurfMlpFeatureMap::new`, which hangs on the pre-existing zero
+    // step sizes in `FeaturePool::create`.
+    fn empty_feature_map() -> SurfMlpFeatureMap {
+        SurfMlpFeatureMap {
+            roi: None,
+            window_origin: (0, 0),
+            width: 0,
+            height: 0,
+            length: 0,
+            feature_pool: FeaturePool::new(),
+            feature_vectors: vec![],
+            feature_vectors_normalized: vec![],
+            feature_valid_indicators: vec![],
+            grad_x: vec![],
+            grad_y: vec![],
+            int_img: Channels::new(FeaturePool::K_NUM_INT_CHANNEL as usize),
+            img_buf: vec![],
+        }
+    }
+
+    #[test]
+    fn rejects_buffer_too_small_without_reshaping_the_map() {
+        let mut fm = empty_feature_map();
+        let input = [0u8; 11];
+
+        match fm.compute_slice(&input, 4, 3) {
+            Err(FeatureError::BufferTooSmall { expected: 12, actual: 11 }) => {}
+            other => panic!("expected BufferTooSmall{{expected: 12, actual: 11}}, got {:?}", other),
+        }
+        assert_eq!(fm.width, 0, "a rejected buffer must not reshape the map");
+    }
+
+    #[test]
+    fn computes_gradients_from_a_valid_buffer() {
+        let input: [u8; 12] = [10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 110, 120];
+
+        let mut fm = empty_feature_map();
+        fm.compute_slice(&input, 4, 3).unwrap();
+
+        let mut expected = empty_feature_map();
+        expected.compute(input.as_ptr(), 4, 3);
+
+        assert_eq!(fm.width, 4);
+        assert_eq!(fm.height, 3);
+        assert_eq!(fm.grad_x, expected.grad_x);
+        assert_eq!(fm.grad_y, expected.grad_y);
+    }
+}
+
 impl FeatureMap for SurfMlpFeatureMap {
     fn compute(&mut self, input: *const u8, width: u32, height: u32) {
         if width == 0 || height == 0 {
@@ -31,6 +616,46 @@ impl FeatureMap for SurfMlpFeatureMap {
 }
 
 impl SurfMlpFeatureMap {
+    /// Like `compute`, but takes a safe `&[u8]` buffer and checks its
+    /// length instead of trusting the caller, returning a `FeatureError`
+    /// rather than reading out of bounds when `input` is too small.
+    pub fn compute_slice(&mut self, input: &[u8], width: u32, height: u32) -> Result<(), FeatureError> {
+        check_buffer_len(input.len(), width, height)?;
+        self.compute(input.as_ptr(), width, height);
+        Ok(())
+    }
+
+    /// Like `compute`, but accepts a multi-channel color buffer and
+    /// converts it to luma first, so callers holding decoded RGB/BGR
+    /// camera or image frames don't have to convert them by hand.
+    /// `input` must hold `width * height * format.channels()` bytes.
+    pub fn compute_with_format(&mut self, input: *const u8, width: u32, height: u32, format: PixelFormat) {
+        let blue_idx = match format.blue_index() {
+            None => {
+                self.compute(input, width, height);
+                return;
+            }
+            Some(blue_idx) => blue_idx,
+        };
+        let red_idx = 2 - blue_idx;
+        let channels = format.channels();
+        let len = (width * height) as usize;
+
+        let mut luma = Vec::with_capacity(len);
+        unsafe {
+            let mut src = input;
+            for _ in 0..len {
+                let r = *src.add(red_idx) as u32;
+                let g = *src.add(1) as u32;
+                let b = *src.add(blue_idx) as u32;
+                luma.push(rgb_to_luma(r, g, b));
+                src = src.add(channels);
+            }
+        }
+
+        self.compute(luma.as_ptr(), width, height);
+    }
+
     pub fn new() -> Self {
         let feature_pool = SurfMlpFeatureMap::create_feature_pool();
         let feature_pool_size = feature_pool.size();
@@ -45,17 +670,17 @@ impl SurfMlpFeatureMap {
 
         SurfMlpFeatureMap {
             roi: None,
+            window_origin: (0, 0),
             width: 0,
             height: 0,
             length: 0,
-            buf_valid_reset: false,
             feature_pool,
             feature_vectors,
             feature_vectors_normalized,
             feature_valid_indicators,
             grad_x: vec![],
             grad_y: vec![],
-            int_img: vec![],
+            int_img: Channels::new(FeaturePool::K_NUM_INT_CHANNEL as usize),
             img_buf: vec![],
         }
     }
@@ -71,6 +696,27 @@ impl SurfMlpFeatureMap {
         feature_pool
     }
 
+    /// Restricts gradient computation in subsequent `compute` calls to the
+    /// given sub-rectangle of the frame (plus a one-pixel gradient halo), so
+    /// that scanning many overlapping windows doesn't redo gradient work on
+    /// rows the caller already knows are irrelevant. The integral image is
+    /// still rebuilt over the whole frame. Once set, the ROI stays in effect
+    /// for every later `compute`; this method cannot clear it.
+    pub fn set_roi(&mut self, roi: Rectangle) {
+        self.roi = Some(roi);
+    }
+
+    /// Records where in frame space the classifier's sample window
+    /// currently sits. An ROI can span the row band shared by many
+    /// candidate windows, so `update_feature_validity` needs this
+    /// separately to translate a feature's sample-local patch into
+    /// frame coordinates before testing it against the ROI. The origin is
+    /// read only when `compute` runs, so while an ROI is set, call this
+    /// before each `compute`; it has no effect when `roi` is `None`.
+    pub fn set_window_origin(&mut self, x: u32, y: u32) {
+        self.window_origin = (x, y);
+    }
+
     fn reshape(&mut self, width: u32, height: u32) {
         self.width = width;
         self.height = height;
@@ -78,152 +724,286 @@ impl SurfMlpFeatureMap {
 
         self.grad_x.resize(self.length, 0);
         self.grad_y.resize(self.length, 0);
-        self.int_img.resize(self.length * FeaturePool::K_NUM_INT_CHANNEL as usize, 0);
+        self.int_img.resize(width as usize, height as usize);
         self.img_buf.resize(self.length, 0);
     }
 
+    /// Row range (half-open) that gradient computation needs to touch
+    /// this frame: the whole frame, or the ROI widened by a
+    /// one-pixel halo on each side so the gradient stencils at the ROI's
+    /// edge still see their neighbours.
+    fn active_rows(&self) -> (usize, usize) {
+        match self.roi {
+            Some(ref roi) => {
+                let y0 = roi.y().saturating_sub(1);
+                let y1 = (roi.y() + roi.height() + 1).min(self.height);
+                (y0 as usize, y1 as usize)
+            }
+            None => (0, self.height as usize),
+        }
+    }
+
+    /// Column range (half-open) analogous to `active_rows`.
+    fn active_cols(&self) -> (usize, usize) {
+        match self.roi {
+            Some(ref roi) => {
+                let x0 = roi.x().saturating_sub(1);
+                let x1 = (roi.x() + roi.width() + 1).min(self.width);
+                (x0 as usize, x1 as usize)
+            }
+            None => (0, self.width as usize),
+        }
+    }
+
     fn compute_gradient_images(&mut self, input: *const u8) {
+        let width = self.width as usize;
+        let (row_start, row_end) = self.active_rows();
+
         unsafe {
-            math::copy_u8_to_i32(input, self.int_img.as_mut_ptr(), self.length);
+            let offset = row_start * width;
+            let len = (row_end - row_start) * width;
+            math::copy_u8_to_i32(input.add(offset), self.img_buf.as_mut_ptr().add(offset), len);
         }
         self.compute_grad_x();
         self.compute_grad_y();
     }
 
     fn compute_grad_x(&mut self) {
-        let input = self.int_img.as_ptr();
-        let dx = self.grad_x.as_mut_ptr();
-        let len = (self.width - 2) as usize;
+        let width = self.width as usize;
+        let (row_start, row_end) = self.active_rows();
+        let (col_start, col_end) = self.active_cols();
 
-        unsafe {
-            for r in 0..self.height {
-                let offset = (r * self.width) as isize;
-                let mut src = input.offset(offset);
-                let mut dest = dx.offset(offset);
-                *dest = ((*(src.offset(1))) - (*src)) << 1;
-                math::vector_sub(src.offset(2), src, dest.offset(1), len);
-
-                let offset = (self.width - 1) as isize;
-                src = src.offset(offset);
-                dest = dest.offset(offset);
-                *dest = ((*src) - (*(src.offset(-1)))) << 1;
+        for row in row_start..row_end {
+            let src = &self.img_buf[row * width..(row + 1) * width];
+            let dest = &mut self.grad_x[row * width..(row + 1) * width];
+
+            if col_start == 0 {
+                dest[0] = (src[1] - src[0]) << 1;
+            }
+
+            let interior_start = col_start.max(1);
+            let interior_end = col_end.min(width - 1);
+            if interior_start < interior_end {
+                let hi = &src[interior_start + 1..interior_end + 1];
+                let lo = &src[interior_start - 1..interior_end - 1];
+                vector_sub_into(&mut dest[interior_start..interior_end], hi, lo);
+            }
+
+            if col_end >= width {
+                dest[width - 1] = (src[width - 1] - src[width - 2]) << 1;
             }
         }
     }
 
     fn compute_grad_y(&mut self) {
-        let input = self.int_img.as_ptr();
-        let mut dy = self.grad_y.as_mut_ptr();
-        let len = self.width as usize;
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let (row_start, row_end) = self.active_rows();
+        let (col_start, col_end) = self.active_cols();
 
-        unsafe {
-            math::vector_sub(input.offset(self.width as isize), input, dy, len);
-            math::vector_add(dy, dy, dy, len);
-
-            for r in 1..(self.height - 1) {
-                let src = input.offset(((r - 1) * self.width) as isize);
-                let dest = dy.offset((r * self.width) as isize);
-                math::vector_sub(src.offset((self.width << 1) as isize), src, dest, len);
+        if row_start == 0 {
+            vector_sub_into(
+                &mut self.grad_y[col_start..col_end],
+                &self.img_buf[width + col_start..width + col_end],
+                &self.img_buf[col_start..col_end],
+            );
+            for d in self.grad_y[col_start..col_end].iter_mut() {
+                *d <<= 1;
             }
+        }
 
-            let offset = ((self.height - 1) * self.width) as isize;
-            dy = dy.offset(offset);
-            math::vector_sub(input.offset(offset), input.offset(offset - self.width as isize), dy, len);
-            math::vector_add(dy, dy, dy, len);
+        let interior_start = row_start.max(1);
+        let interior_end = row_end.min(height - 1);
+        for r in interior_start..interior_end {
+            let prev = (r - 1) * width;
+            let next = (r + 1) * width;
+            vector_sub_into(
+                &mut self.grad_y[r * width + col_start..r * width + col_end],
+                &self.img_buf[next + col_start..next + col_end],
+                &self.img_buf[prev + col_start..prev + col_end],
+            );
+        }
+
+        if row_end >= height {
+            let last = height - 1;
+            let prev = (last - 1) * width;
+            vector_sub_into(
+                &mut self.grad_y[last * width + col_start..last * width + col_end],
+                &self.img_buf[last * width + col_start..last * width + col_end],
+                &self.img_buf[prev + col_start..prev + col_end],
+            );
+            for d in self.grad_y[last * width + col_start..last * width + col_end].iter_mut() {
+                *d <<= 1;
+            }
         }
     }
 
     fn compute_integral_images(&mut self) {
-        let grad_x_ptr = self.grad_x.as_ptr();
-        let grad_y_ptr = self.grad_y.as_ptr();
-        let img_buf_ptr = self.img_buf.as_ptr();
+        // `integral()`'s vertical/horizontal prefix sums run top-to-bottom,
+        // left-to-right in place, so every row must hold fresh raw gradient
+        // data before they run: a row left over from a prior ROI-restricted
+        // call already holds *cumulative* data from that pass, and feeding
+        // it in again as if it were raw would corrupt it and every row
+        // below it. So unlike `compute_gradient_images`, this always fills
+        // and recomputes the whole buffer, even when an ROI is set.
+        let len = self.length;
 
-        unsafe {
-            self.fill_integral_channel(grad_x_ptr, 0);
-            self.fill_integral_channel(grad_y_ptr, 4);
-            math::abs(grad_x_ptr, img_buf_ptr as *mut i32, self.length);
-            self.fill_integral_channel(img_buf_ptr, 1);
-            math::abs(grad_y_ptr, img_buf_ptr as *mut i32, self.length);
-            self.fill_integral_channel(img_buf_ptr, 5);
-        }
+        self.int_img.fill_channel(&self.grad_x[..len], 0);
+        self.int_img.fill_channel(&self.grad_y[..len], 4);
+
+        vector_abs_into(&mut self.img_buf[..len], &self.grad_x[..len]);
+        self.int_img.fill_channel(&self.img_buf[..len], 1);
+
+        vector_abs_into(&mut self.img_buf[..len], &self.grad_y[..len]);
+        self.int_img.fill_channel(&self.img_buf[..len], 5);
 
         self.mask_integral_channel();
         self.integral();
+        self.update_feature_validity();
     }
 
-    unsafe fn fill_integral_channel(&mut self, mut src: *const i32, ch: u32) {
-        let mut dest = self.int_img.as_mut_ptr().offset(ch as isize);
-        for _ in 0..self.length {
-            *dest = *src;
-            *dest.offset(2) = *src;
-            dest = dest.offset(FeaturePool::K_NUM_INT_CHANNEL as isize);
-            src = src.offset(1);
+    /// Marks every feature whose sample-space patch falls outside the
+    /// current ROI as invalid, so the (comparatively expensive) feature
+    /// vector extraction that runs after this can skip it entirely. `roi`
+    /// is in frame space while `patch` is sample-space (relative to the
+    /// classifier's own `[0, sample_width) x [0, sample_height)` window),
+    /// so `patch` is translated by `window_origin` into frame space
+    /// before being tested against `roi`.
+    fn update_feature_validity(&mut self) {
+        let roi = match self.roi.as_ref() {
+            Some(roi) => roi,
+            None => {
+                self.feature_valid_indicators.clear();
+                self.feature_valid_indicators.resize(self.feature_pool.size(), true);
+                return;
+            }
+        };
+
+        let (origin_x, origin_y) = self.window_origin;
+        self.feature_valid_indicators.clear();
+        for feature in self.feature_pool.features.iter() {
+            let patch = &feature.patch;
+            let frame_x = origin_x + patch.x();
+            let frame_y = origin_y + patch.y();
+            let valid = frame_x >= roi.x()
+                && frame_y >= roi.y()
+                && frame_x + patch.width() <= roi.x() + roi.width()
+                && frame_y + patch.height() <= roi.y() + roi.height();
+            self.feature_valid_indicators.push(valid);
         }
     }
 
     fn mask_integral_channel(&mut self) {
-        let grad_x_ptr = self.grad_x.as_ptr();
-        let grad_y_ptr = self.grad_y.as_ptr();
+        const XOR_BITS: [i32; 4] = [-1, -1, 0, 0];
+        let num_channel = self.int_img.num_channel;
 
-        let mut dx: i32;
-        let mut dy: i32;
-        let mut dx_mask: i32;
-        let mut dy_mask: i32;
-        let mut cmp: u32;
-        let xor_bits: Vec<u32> = vec![0xffffffff, 0xffffffff, 0, 0];
+        for channels in self.int_img.data.chunks_exact_mut(num_channel) {
+            let dx = self.grad_x[1];
+            let dy = self.grad_y[1];
+            let (y_channels, x_channels) = channels.split_at_mut(num_channel / 2);
 
-        let mut src = self.int_img.as_mut_ptr();
-        unsafe {
-            for i in 0..self.length {
-                dx = *grad_x_ptr.offset(1);
-                dy = *grad_y_ptr.offset(1);
-
-                cmp = if dy < 0 { 0xffffffff } else { 0x0 };
-                for j in 0..4 {
-                    dy_mask = (cmp ^ xor_bits[j]) as i32;
-                    *src = *src & dy_mask;
-                    src = src.offset(1);
-                }
+            let cmp_y = if dy < 0 { -1 } else { 0 };
+            for (v, &mask) in y_channels.iter_mut().zip(XOR_BITS.iter()) {
+                *v &= cmp_y ^ mask;
+            }
 
-                cmp = if dx < 0 { 0xffffffff } else { 0x0 };
-                for j in 0..4 {
-                    dx_mask = (cmp ^ xor_bits[j]) as i32;
-                    *src = *src & dx_mask;
-                    src = src.offset(1);
-                }
+            let cmp_x = if dx < 0 { -1 } else { 0 };
+            for (v, &mask) in x_channels.iter_mut().zip(XOR_BITS.iter()) {
+                *v &= cmp_x ^ mask;
             }
         }
     }
 
     fn integral(&mut self) {
-        let data = self.int_img.as_ptr();
-        let len = (FeaturePool::K_NUM_INT_CHANNEL * self.width) as usize;
+        let height = self.height as usize;
+        let num_channel = self.int_img.num_channel;
+        let row_len = self.int_img.width * num_channel;
 
-        unsafe {
-            for r in 0..(self.height - 1) as isize {
-                let row1 = data.offset(r * len as isize);
-                let row2 = row1.offset(len as isize);
-                math::vector_add(row1, row2, row2 as *mut i32, len);
-            }
+        for r in 1..height {
+            let (above, below) = self.int_img.data.split_at_mut(r * row_len);
+            let prev_row = &above[(r - 1) * row_len..r * row_len];
+            let curr_row = &mut below[..row_len];
+            vector_add_assign(curr_row, prev_row);
+        }
 
-            for r in 0..self.height as isize {
-                SurfMlpFeatureMap::vector_cumulative_add(
-                    data.offset(r * len as isize), len, FeaturePool::K_NUM_INT_CHANNEL);
-            }
+        for r in 0..height {
+            SurfMlpFeatureMap::vector_cumulative_add(self.int_img.row_mut(r), num_channel);
         }
     }
 
-    unsafe fn vector_cumulative_add(x: *const i32, len: usize, num_channel: u32) {
-        let num_channel = num_channel as usize;
-        let cols = len / num_channel - 1;
-        for i in 0..cols as isize {
-            let col1 = x.offset(i * num_channel as isize);
-            let col2 = col1.offset(num_channel as isize);
-            math::vector_add(col1, col2, col2 as *mut i32, num_channel);
+    fn vector_cumulative_add(row: &mut [i32], num_channel: usize) {
+        let cols = row.len() / num_channel;
+        for c in 1..cols {
+            let (left, right) = row.split_at_mut(c * num_channel);
+            let prev_col = &left[(c - 1) * num_channel..c * num_channel];
+            let curr_col = &mut right[..num_channel];
+            vector_add_assign(curr_col, prev_col);
         }
     }
 }
 
+#[cfg(test)]
+mod integral_image_tests {
+    use super::{Channels, FeaturePool, SurfMlpFeatureMap};
+    use common::Rectangle;
+
+    // Builds a feature map without going through `SurfMlpFeatureMap::new`,
+    // which would hang: `create_feature_pool` leaves the pool's step sizes
+    // (`patch_move_step_x`/`_y`, `patch_size_inc_step`) at zero, so the
+    // loops in `FeaturePool::create` and `collect_features` never advance,
+    // a pre-existing bug this series doesn't touch. An empty `FeaturePool` sidesteps it entirely, since
+    // nothing here exercises feature extraction.
+    fn test_feature_map(width: u32, height: u32) -> SurfMlpFeatureMap {
+        let length = (width * height) as usize;
+        let mut int_img = Channels::new(FeaturePool::K_NUM_INT_CHANNEL as usize);
+        int_img.resize(width as usize, height as usize);
+
+        SurfMlpFeatureMap {
+            roi: None,
+            window_origin: (0, 0),
+            width,
+            height,
+            length,
+            feature_pool: FeaturePool::new(),
+            feature_vectors: vec![],
+            feature_vectors_normalized: vec![],
+            feature_valid_indicators: vec![],
+            grad_x: vec![0; length],
+            grad_y: vec![0; length],
+            int_img,
+            img_buf: vec![0; length],
+        }
+    }
+
+    #[test]
+    fn roi_restricted_frame_is_not_corrupted_by_the_previous_frame() {
+        let mut fm = test_feature_map(2, 3);
+        fm.grad_x = vec![1, 2, 3, 4, 5, 6];
+        fm.grad_y = vec![10, 20, 30, 40, 50, 60];
+        fm.compute_integral_images();
+
+        // Next frame: different gradients, and an ROI (as a caller
+        // scanning overlapping windows would set) restricted to the
+        // last row.
+        fm.roi = Some(Rectangle::new(0, 2, 2, 1));
+        fm.grad_x = vec![7, 8, 9, 10, 11, 12];
+        fm.grad_y = vec![70, 80, 90, 100, 110, 120];
+        fm.compute_integral_images();
+        let got = fm.int_img.data.clone();
+
+        let mut fresh = test_feature_map(2, 3);
+        fresh.grad_x = vec![7, 8, 9, 10, 11, 12];
+        fresh.grad_y = vec![70, 80, 90, 100, 110, 120];
+        fresh.compute_integral_images();
+
+        assert_eq!(
+            got, fresh.int_img.data,
+            "integral image for the second, ROI-restricted frame must match a fresh computation \
+             on the same gradients, not carry over stale cumulative data from the first frame"
+        );
+    }
+}
+
 struct FeaturePool {
     sample_width: u32,
     sample_height: u32,